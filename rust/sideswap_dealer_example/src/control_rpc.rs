@@ -0,0 +1,171 @@
+use jsonrpsee::core::Error as RpcError;
+use jsonrpsee::server::{ServerBuilder, ServerHandle};
+use jsonrpsee::types::error::CallError;
+use jsonrpsee::RpcModule;
+use rust_decimal::prelude::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct Server {
+    pub bind_addr: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum ControlRequest {
+    ListActiveSwaps,
+    ListReservedUtxos,
+    GetInventory,
+    PauseTrading,
+    ResumeTrading,
+    SetProfitRatio { profit_ratio: Decimal },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveSwapInfo {
+    pub order_id: String,
+    pub proposal: i64,
+    pub change_amount: i64,
+    pub sell_asset: String,
+    pub state: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReservedUtxoInfo {
+    pub txid: String,
+    pub vout: u32,
+    pub amount: i64,
+    pub order_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryEntry {
+    pub asset_id: String,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ControlResponse {
+    ActiveSwaps(Vec<ActiveSwapInfo>),
+    ReservedUtxos(Vec<ReservedUtxoInfo>),
+    Inventory(Vec<InventoryEntry>),
+    Ack,
+}
+
+// Starts the control/management WebSocket JSON-RPC server on its own Tokio runtime.
+// Every call is handed to `dispatch`, which forwards it to the main event loop and
+// waits (with a bound, so a wedged main loop turns into an RPC error rather than a
+// permanent hang on this runtime's worker thread) for the reply. The main loop answers
+// from a task that holds the shared dealer state lock, so this server never touches
+// that state directly.
+pub fn start<F>(settings: &Server, dispatch: F) -> std::thread::JoinHandle<()>
+where
+    F: Fn(ControlRequest) -> Result<ControlResponse, String> + Send + Sync + 'static,
+{
+    let bind_addr = settings.bind_addr.clone();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().expect("control RPC runtime init failed");
+        runtime.block_on(run_server(bind_addr, dispatch));
+    })
+}
+
+async fn run_server<F>(bind_addr: String, dispatch: F)
+where
+    F: Fn(ControlRequest) -> Result<ControlResponse, String> + Send + Sync + 'static,
+{
+    let server = ServerBuilder::default()
+        .build(&bind_addr)
+        .await
+        .expect("can't bind control RPC server");
+
+    let mut module = RpcModule::new(dispatch);
+
+    module
+        .register_method("list_active_swaps", |_params, dispatch| {
+            match dispatch(ControlRequest::ListActiveSwaps).map_err(dispatch_failed)? {
+                ControlResponse::ActiveSwaps(swaps) => Ok(swaps),
+                _ => Err(unexpected_response()),
+            }
+        })
+        .expect("registering list_active_swaps failed");
+
+    module
+        .register_method("list_reserved_utxos", |_params, dispatch| {
+            match dispatch(ControlRequest::ListReservedUtxos).map_err(dispatch_failed)? {
+                ControlResponse::ReservedUtxos(utxos) => Ok(utxos),
+                _ => Err(unexpected_response()),
+            }
+        })
+        .expect("registering list_reserved_utxos failed");
+
+    module
+        .register_method("get_inventory", |_params, dispatch| {
+            match dispatch(ControlRequest::GetInventory).map_err(dispatch_failed)? {
+                ControlResponse::Inventory(inventory) => Ok(inventory),
+                _ => Err(unexpected_response()),
+            }
+        })
+        .expect("registering get_inventory failed");
+
+    module
+        .register_method("pause_trading", |_params, dispatch| {
+            dispatch(ControlRequest::PauseTrading).map_err(dispatch_failed)?;
+            Ok(())
+        })
+        .expect("registering pause_trading failed");
+
+    module
+        .register_method("resume_trading", |_params, dispatch| {
+            dispatch(ControlRequest::ResumeTrading).map_err(dispatch_failed)?;
+            Ok(())
+        })
+        .expect("registering resume_trading failed");
+
+    module
+        .register_method("set_profit_ratio", |params, dispatch| {
+            let profit_ratio: f64 = params.one()?;
+            let profit_ratio = Decimal::from_f64(profit_ratio)
+                .ok_or_else(|| invalid_params("profit_ratio is not a finite number".to_owned()))?;
+            if profit_ratio < crate::MIN_PROFIT_RATIO {
+                return Err(invalid_params(format!(
+                    "profit_ratio must be at least {}",
+                    crate::MIN_PROFIT_RATIO
+                )));
+            }
+            dispatch(ControlRequest::SetProfitRatio { profit_ratio }).map_err(dispatch_failed)?;
+            Ok(())
+        })
+        .expect("registering set_profit_ratio failed");
+
+    let handle: ServerHandle = server.start(module).expect("starting control RPC server failed");
+    handle.stopped().await;
+}
+
+fn unexpected_response() -> RpcError {
+    RpcError::Call(CallError::Custom(jsonrpsee::types::error::ErrorObject::owned(
+        -32000,
+        "unexpected control RPC dispatch response",
+        None::<()>,
+    )))
+}
+
+// `dispatch` failed to get an answer from the main loop in time (or it reported its own
+// error); surface that as an RPC error rather than blocking the caller indefinitely.
+fn dispatch_failed(message: String) -> RpcError {
+    RpcError::Call(CallError::Custom(jsonrpsee::types::error::ErrorObject::owned(
+        -32000,
+        message,
+        None::<()>,
+    )))
+}
+
+// Standard JSON-RPC "Invalid params" code, used for request validation failures so a bad
+// remote call gets an error response instead of taking down the dispatch task.
+fn invalid_params(message: String) -> RpcError {
+    RpcError::Call(CallError::Custom(jsonrpsee::types::error::ErrorObject::owned(
+        -32602,
+        message,
+        None::<()>,
+    )))
+}
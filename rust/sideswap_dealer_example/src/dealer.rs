@@ -0,0 +1,121 @@
+use rust_decimal::prelude::*;
+use rust_decimal::Decimal;
+use sideswap_api::Asset;
+use types::Amount;
+
+use crate::prices;
+
+#[cfg(test)]
+use rust_decimal_macros::dec;
+
+const SATS_PER_UNIT: i64 = 100_000_000;
+
+// Computes the amount the dealer sends back for a quote, given what it is asked
+// to receive. All math is done in `Decimal` to avoid the rounding drift and
+// silent precision loss that comes with `f64` at satoshi scale, and rounds down
+// in the dealer's favor so the enforced `profit_ratio` invariant can't be broken
+// by rounding. `profit_ratio` is read fresh from the shared dealer state on every
+// call, since it can be adjusted at runtime through the control RPC. The fair-value
+// conversion of `send_amount` is *divided* by `profit_ratio` (> 1) so the dealer
+// always sends out less value than it receives, never more.
+pub fn get_proposal(
+    profit_ratio: Decimal,
+    send_amount: Amount,
+    other_asset: &Asset,
+    dealer_send_bitcoin: bool,
+) -> anyhow::Result<Amount> {
+    let sats_per_unit = Decimal::from(SATS_PER_UNIT);
+
+    let send_amount = Decimal::from(send_amount.to_sat())
+        .checked_div(sats_per_unit)
+        .ok_or_else(|| anyhow!("send_amount overflow"))?;
+
+    let rate = prices::get_rate(&other_asset.asset_id, dealer_send_bitcoin)?;
+
+    let proposal = send_amount
+        .checked_mul(rate)
+        .and_then(|value| value.checked_div(profit_ratio))
+        .ok_or_else(|| anyhow!("proposal overflow"))?;
+
+    let proposal_sats = proposal
+        .checked_mul(sats_per_unit)
+        .ok_or_else(|| anyhow!("proposal overflow"))?
+        .floor()
+        .to_i64()
+        .ok_or_else(|| anyhow!("proposal doesn't fit in i64"))?;
+
+    Ok(Amount::from_sat(proposal_sats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(asset_id: &str, ticker: &'static str) -> Asset {
+        Asset {
+            asset_id: asset_id.to_owned(),
+            ticker,
+        }
+    }
+
+    #[test]
+    fn rounds_down_in_the_dealers_favor() {
+        // Rate of 2 BTC per unit: 3 sats * 2 / 1.0025 = 5.98503... sats, must floor to
+        // 5, never round up to 6 (which would hand the dealer's fractional-satoshi edge
+        // back to the counterparty).
+        prices::update_rate("other".to_owned(), dec!(2));
+        let send_amount = Amount::from_sat(3);
+        let other_asset = asset("other", "OTHER");
+
+        let proposal = get_proposal(dec!(1.0025), send_amount, &other_asset, true).unwrap();
+
+        assert_eq!(proposal.to_sat(), 5);
+    }
+
+    #[test]
+    fn dealer_never_sends_more_value_than_it_receives_divided_by_profit_ratio() {
+        // 100 units of an asset priced at 0.00002 BTC/unit is 0.002 BTC of fair value
+        // received; the dealer must send back strictly less than that once profit_ratio
+        // is applied, for a non-trivial rate and profit_ratio (not just 1:1 or 2x).
+        prices::update_rate("other_small_rate".to_owned(), dec!(0.00002));
+        let send_amount = Amount::from_sat(100 * SATS_PER_UNIT);
+        let other_asset = asset("other_small_rate", "OTHER");
+        let profit_ratio = dec!(1.002);
+
+        let proposal = get_proposal(profit_ratio, send_amount, &other_asset, true).unwrap();
+
+        let value_received = dec!(100) * dec!(0.00002);
+        let value_sent = Decimal::from(proposal.to_sat()) / Decimal::from(SATS_PER_UNIT);
+        assert!(value_received >= value_sent * profit_ratio);
+    }
+
+    #[test]
+    fn inverts_the_rate_when_dealer_sends_the_asset() {
+        prices::update_rate("other".to_owned(), dec!(2));
+        let send_amount = Amount::from_sat(SATS_PER_UNIT);
+        let other_asset = asset("other", "OTHER");
+
+        let proposal = get_proposal(dec!(1), send_amount, &other_asset, false).unwrap();
+
+        // Sending 1 BTC-equivalent of bitcoin back at the reciprocal of a 2x rate should
+        // give 0.5 units of the other asset.
+        assert_eq!(proposal.to_sat(), SATS_PER_UNIT / 2);
+    }
+
+    #[test]
+    fn errors_instead_of_panicking_without_a_known_rate() {
+        let send_amount = Amount::from_sat(1);
+        let other_asset = asset("unknown_asset", "UNK");
+
+        assert!(get_proposal(dec!(1), send_amount, &other_asset, true).is_err());
+    }
+
+    #[test]
+    fn errors_instead_of_overflowing_on_an_extreme_rate() {
+        prices::update_rate("huge".to_owned(), Decimal::MAX);
+        let send_amount = Amount::from_sat(SATS_PER_UNIT);
+        let other_asset = asset("huge", "HUGE");
+
+        assert!(get_proposal(dec!(1), send_amount, &other_asset, true).is_err());
+    }
+}
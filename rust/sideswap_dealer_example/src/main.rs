@@ -1,12 +1,19 @@
 use clap::{App, Arg};
 use rpc::RpcServer;
+use rust_decimal::prelude::*;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 use serde::Deserialize;
 use sideswap_api::*;
 use sideswap_common::*;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
 use types::{Amount, TxOut};
 
 mod btc_zmq;
+mod control_rpc;
 mod dealer;
 mod prices;
 mod rpc;
@@ -21,7 +28,7 @@ const SERVER_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_se
 const UNSPENT_MIN_CONF: i32 = 1;
 
 // Sanity check
-const MIN_PROFIT_RATIO: f64 = 1.002;
+pub(crate) const MIN_PROFIT_RATIO: Decimal = dec!(1.002);
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -31,7 +38,11 @@ pub struct Settings {
     rpc: RpcServer,
     api_key: String,
     zmq: btc_zmq::Server,
-    profit_ratio: f64,
+    profit_ratio: Decimal,
+    control_rpc: control_rpc::Server,
+    settlement_tag: String,
+    swap_timeout_secs: u64,
+    bounce_fee: i64,
 }
 
 enum Msg {
@@ -39,6 +50,10 @@ enum Msg {
     Disconnected,
     Notification(Notification),
     NewBlock,
+    Control(
+        control_rpc::ControlRequest,
+        std::sync::mpsc::Sender<control_rpc::ControlResponse>,
+    ),
 }
 
 #[derive(Clone, Debug)]
@@ -53,6 +68,13 @@ struct ActiveSwap {
     change_amount: i64,
     sell_asset: String,
     swap: Option<Swap>,
+    deadline: std::time::Instant,
+    // Set while a notification handler is between locking in its plan and locking back
+    // in to report the result, so `bounce_stalled_swap` (which runs concurrently off
+    // `NewBlock`) can tell a swap that is legitimately mid-RPC-round-trip apart from one
+    // that's actually stalled, and never reuses its UTXOs out from under it.
+    busy: bool,
+    bounce_txid: Option<String>,
 }
 
 type Utxos = BTreeMap<TxOut, Utxo>;
@@ -66,7 +88,93 @@ fn free_reservation(order_id: &OrderId, utxos: &mut Utxos) {
     }
 }
 
-fn main() {
+// Marks `order_id`'s swap as actively progressing and pushes its deadline out, so a
+// notification that's just slow (normal RPC/user latency) doesn't get bounced mid-flight.
+// Returns `false` (and does nothing) if the swap is gone or has already been bounced, so
+// callers can bail out instead of panicking on state that raced out from under them.
+fn mark_swap_active(
+    swaps: &mut BTreeMap<OrderId, ActiveSwap>,
+    order_id: &OrderId,
+    swap_timeout: std::time::Duration,
+) -> bool {
+    match swaps.get_mut(order_id) {
+        Some(active_swap) if active_swap.bounce_txid.is_none() => {
+            active_swap.busy = true;
+            active_swap.deadline = std::time::Instant::now() + swap_timeout;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn mark_swap_idle(swaps: &mut BTreeMap<OrderId, ActiveSwap>, order_id: &OrderId) {
+    if let Some(active_swap) = swaps.get_mut(order_id) {
+        active_swap.busy = false;
+    }
+}
+
+// All dealer state shared between the concurrently running notification handlers.
+struct SharedState {
+    assets: Vec<Asset>,
+    utxos: Utxos,
+    swaps: BTreeMap<OrderId, ActiveSwap>,
+    trading_paused: bool,
+    profit_ratio: Decimal,
+}
+
+// Routes server responses back to whichever task is awaiting them, so many
+// requests (quotes, PSBT round-trips, ...) can be outstanding at once instead of
+// serializing everything through a single in-flight request id.
+#[derive(Clone)]
+struct RequestSender {
+    ws_tx: std::sync::mpsc::Sender<ws::WrappedRequest>,
+    current_request_id: Arc<AtomicI64>,
+    pending: Arc<Mutex<HashMap<i64, oneshot::Sender<Result<Response, Error>>>>>,
+}
+
+impl RequestSender {
+    async fn send(&self, request: Request) -> Result<Response, Error> {
+        let request_id = self.current_request_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id, tx);
+        self.ws_tx
+            .send(ws::WrappedRequest::Request(RequestMessage::Request(
+                RequestId::Int(request_id),
+                request,
+            )))
+            .unwrap();
+        match tokio::time::timeout(SERVER_REQUEST_TIMEOUT, rx).await {
+            Ok(result) => result.expect("response channel closed before a reply arrived"),
+            Err(_) => {
+                // The server may still answer after this point; drop the slot now so a
+                // timed-out request doesn't leak in `pending` for the rest of the process.
+                self.pending.lock().unwrap().remove(&request_id);
+                panic!("request timeout");
+            }
+        }
+    }
+
+    // Takes the waiting sender for `request_id` out of `pending`, if any. Pulled out of
+    // the response-reading loop so the id-routing behaviour (deliver to the right
+    // waiter, drop anything with no matching waiter) can be exercised without a real
+    // websocket or oneshot channel round-trip.
+    fn take_pending(&self, request_id: i64) -> Option<oneshot::Sender<Result<Response, Error>>> {
+        self.pending.lock().unwrap().remove(&request_id)
+    }
+}
+
+macro_rules! send_request {
+    ($sender:expr, $t:ident, $value:expr) => {
+        match $sender.send(Request::$t($value)).await {
+            Ok(Response::$t(value)) => Ok(value),
+            Ok(_) => panic!("unexpected response type"),
+            Err(error) => Err(error),
+        }
+    };
+}
+
+#[tokio::main]
+async fn main() {
     let matches = App::new("sideswap_dealer")
         .arg(Arg::with_name("config").required(true))
         .get_matches();
@@ -83,21 +191,33 @@ fn main() {
 
     log4rs::init_file(&settings.log_settings, Default::default()).expect("can't open log settings");
 
-    let (msg_tx, msg_rx) = std::sync::mpsc::channel::<Msg>();
+    assert!(settings.profit_ratio >= MIN_PROFIT_RATIO);
+
+    let max_trade_amount = Amount::from_sat(
+        Decimal::from_f64(settings.max_trade_size)
+            .and_then(|value| value.checked_mul(Decimal::from(100_000_000i64)))
+            .and_then(|value| value.to_i64())
+            .expect("max_trade_size out of range"),
+    );
+    let swap_timeout = std::time::Duration::from_secs(settings.swap_timeout_secs);
+    let settings = Arc::new(settings);
+
+    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::unbounded_channel::<Msg>();
     let env_data = types::env_data(settings.env);
     let (ws_tx, ws_rx) = ws::start(
         env_data.host.to_owned(),
         env_data.port.to_owned(),
         env_data.use_tls,
     );
-    let current_request_id = std::sync::Arc::new(std::sync::atomic::AtomicI64::new(0));
-    let (resp_tx, resp_rx) = std::sync::mpsc::channel::<Result<Response, Error>>();
-    let current_request_id_copy = std::sync::Arc::clone(&current_request_id);
-    let max_trade_amount = Amount::from_bitcoin(settings.max_trade_size);
 
-    assert!(settings.profit_ratio >= MIN_PROFIT_RATIO);
+    let request_sender = RequestSender {
+        ws_tx: ws_tx.clone(),
+        current_request_id: Arc::new(AtomicI64::new(0)),
+        pending: Arc::new(Mutex::new(HashMap::new())),
+    };
 
     let msg_tx_copy = msg_tx.clone();
+    let request_sender_copy = request_sender.clone();
     std::thread::spawn(move || {
         for msg in ws_rx {
             match msg {
@@ -111,18 +231,17 @@ fn main() {
                     Some(RequestId::Int(request_id)),
                     response,
                 )) => {
-                    let pending_request_id =
-                        current_request_id_copy.load(std::sync::atomic::Ordering::Relaxed);
-                    if request_id != pending_request_id {
-                        panic!(
-                            "unexpected request_id response: {}, expecting: {}",
-                            request_id, pending_request_id
-                        );
+                    match request_sender_copy.take_pending(request_id) {
+                        Some(sender) => {
+                            let _ = sender.send(response);
+                        }
+                        None => {
+                            warn!("dropping response for unexpected request_id: {}", request_id);
+                        }
                     }
-                    resp_tx.send(response).unwrap();
                 }
                 ws::WrappedResponse::Response(ResponseMessage::Response(_, _)) => {
-                    panic!("invalid request_id response");
+                    warn!("dropping response with missing or invalid request_id");
                 }
                 ws::WrappedResponse::Response(ResponseMessage::Notification(notification)) => {
                     msg_tx_copy.send(Msg::Notification(notification)).unwrap();
@@ -131,59 +250,46 @@ fn main() {
         }
     });
 
-    let send_request = |request: Request| -> Result<Response, Error> {
-        current_request_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let request_id = current_request_id.load(std::sync::atomic::Ordering::Relaxed);
-        ws_tx
-            .send(ws::WrappedRequest::Request(RequestMessage::Request(
-                RequestId::Int(request_id),
-                request,
-            )))
-            .unwrap();
-        resp_rx
-            .recv_timeout(SERVER_REQUEST_TIMEOUT)
-            .expect("request timeout")
-    };
-
     let msg_tx_copy = msg_tx.clone();
     btc_zmq::connect(&settings.zmq, move |topic, _| {
-        match topic {
-            btc_zmq::BtcTopic::PubHashBlock => {
-                msg_tx_copy.send(Msg::NewBlock).unwrap();
-            }
-            _ => {}
-        };
+        if let btc_zmq::BtcTopic::PubHashBlock = topic {
+            msg_tx_copy.send(Msg::NewBlock).unwrap();
+        }
     });
 
-    macro_rules! send_request {
-        ($t:ident, $value:expr) => {
-            match send_request(Request::$t($value)) {
-                Ok(Response::$t(value)) => Ok(value),
-                Ok(_) => panic!("unexpected response type"),
-                Err(error) => Err(error),
-            }
-        };
-    }
+    let msg_tx_copy = msg_tx.clone();
+    let _control_rpc_handle = control_rpc::start(&settings.control_rpc, move |request| {
+        let (reply_tx, reply_rx) = std::sync::mpsc::channel();
+        msg_tx_copy.send(Msg::Control(request, reply_tx)).unwrap();
+        // Bound the wait like any other server round-trip, so a slow or wedged main loop
+        // turns into a clean RPC error for the caller instead of hanging this thread forever.
+        reply_rx
+            .recv_timeout(SERVER_REQUEST_TIMEOUT)
+            .map_err(|_| "control RPC reply timed out".to_owned())
+    });
 
-    let mut assets = Vec::new();
-    let mut utxos = Utxos::new();
-    let mut swaps: BTreeMap<OrderId, ActiveSwap> = BTreeMap::new();
+    let shared = Arc::new(tokio::sync::Mutex::new(SharedState {
+        assets: Vec::new(),
+        utxos: Utxos::new(),
+        swaps: BTreeMap::new(),
+        trading_paused: false,
+        profit_ratio: settings.profit_ratio,
+    }));
 
     msg_tx.send(Msg::NewBlock).unwrap();
 
-    let rpc_http_client = reqwest::blocking::Client::builder()
+    let rpc_http_client = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(5))
         .build()
         .expect("http client construction failed");
 
-    loop {
-        let msg = msg_rx.recv().unwrap();
-
+    while let Some(msg) = msg_rx.recv().await {
         match msg {
             Msg::Connected => {
                 info!("connected to server");
 
                 send_request!(
+                    request_sender,
                     LoginDealer,
                     LoginDealerRequest {
                         api_key: settings.api_key.clone(),
@@ -191,284 +297,672 @@ fn main() {
                 )
                 .expect("dealer login failed");
 
-                assets = send_request!(Assets, None)
+                let assets = send_request!(request_sender, Assets, None)
                     .expect("loading assets failed")
                     .assets;
                 types::check_assets(settings.env, &assets);
+                shared.lock().await.assets = assets;
             }
 
             Msg::Disconnected => {
                 warn!("disconnected from server");
             }
 
-            Msg::Notification(notification) => match notification {
-                Notification::RfqCreated(rfq) => {
-                    let rfq_recv_asset = assets
-                        .iter()
-                        .find(|v| v.asset_id == rfq.rfq.recv_asset)
-                        .expect("buy_asset must be known");
-                    let ref_send_asset = assets
-                        .iter()
-                        .find(|v| v.asset_id == rfq.rfq.send_asset)
-                        .expect("sell_asset must be known");
-                    info!(
-                        "new RFQ received, order_id: {}, dealer deleiver: {}, dealer receive: {}",
-                        &rfq.order_id, &rfq_recv_asset.ticker, &ref_send_asset.ticker
-                    );
-
-                    assert!(
-                        rfq_recv_asset.ticker == TICKER_LBTC
-                            || ref_send_asset.ticker == TICKER_LBTC
-                    );
+            Msg::Notification(Notification::RfqCreated(rfq)) => {
+                let shared = Arc::clone(&shared);
+                let request_sender = request_sender.clone();
+                tokio::spawn(async move {
+                    handle_rfq_created(shared, request_sender, max_trade_amount, swap_timeout, rfq).await;
+                });
+            }
 
-                    let dealer_send_bitcoin = rfq_recv_asset.ticker == TICKER_LBTC;
-                    let other_asset = if dealer_send_bitcoin {
-                        ref_send_asset
-                    } else {
-                        rfq_recv_asset
-                    };
-                    let rfq_send_amount = Amount::from_sat(rfq.rfq.send_amount);
-
-                    let proposal = dealer::get_proposal(
-                        &settings,
-                        rfq_send_amount,
-                        &other_asset,
-                        dealer_send_bitcoin,
-                    );
-                    let proposal = match proposal {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("can't get proposal: {}", e);
-                            continue;
-                        }
-                    };
-
-                    let bitcoin_amount = if dealer_send_bitcoin {
-                        proposal
-                    } else {
-                        rfq_send_amount
-                    };
-
-                    if bitcoin_amount > max_trade_amount {
-                        info!(
-                            "amount to trade is more than allowed: {} > {}",
-                            bitcoin_amount, max_trade_amount
-                        );
-                        continue;
+            Msg::Notification(Notification::RfqRemoved(rfq)) => {
+                let shared = Arc::clone(&shared);
+                tokio::spawn(async move {
+                    if rfq.status != RfqStatus::Accepted {
+                        free_reservation(&rfq.order_id, &mut shared.lock().await.utxos);
                     }
+                });
+            }
 
-                    let available_utxos: Vec<i64> = utxos
-                        .values()
-                        .filter(|utxo| {
-                            utxo.item.asset == rfq_recv_asset.asset_id && utxo.reserve.is_none()
-                        })
-                        .map(|utxo| utxo.amount)
-                        .collect();
-                    let total: i64 = available_utxos.iter().sum();
-                    if total < proposal.to_sat() {
-                        info!(
-                            "not enough amount to make proposal: {}, required: {}",
-                            total, proposal
-                        );
-                        continue;
-                    }
+            Msg::Notification(Notification::Swap(swap)) => {
+                let settings = Arc::clone(&settings);
+                let shared = Arc::clone(&shared);
+                let request_sender = request_sender.clone();
+                let rpc_http_client = rpc_http_client.clone();
+                tokio::spawn(async move {
+                    handle_swap_notification(settings, shared, request_sender, rpc_http_client, swap_timeout, swap)
+                        .await;
+                });
+            }
 
-                    let result = types::select_utxo(available_utxos, proposal.to_sat());
-                    let change_amount = result.iter().sum::<i64>() - proposal.to_sat();
-                    assert!(change_amount >= 0);
-
-                    info!("sending quote: {}", proposal);
-                    let quote_result = send_request!(
-                        MatchQuote,
-                        MatchQuoteRequest {
-                            quote: MatchQuote {
-                                order_id: rfq.order_id.clone(),
-                                send_amount: proposal.to_sat(),
-                                utxo_count: result.len() as i32,
-                                with_change: change_amount > 0,
-                            },
-                        }
-                    );
-                    if let Err(e) = quote_result {
-                        error!("sending quote failed: {}", &e.message);
-                        continue;
-                    };
-                    debug!("sending quote succeed");
-
-                    for &amount in result.iter() {
-                        let utxo = utxos
-                            .values_mut()
-                            .find(|utxo| {
-                                utxo.item.asset == rfq_recv_asset.asset_id
-                                    && utxo.reserve.is_none()
-                                    && utxo.amount == amount
-                            })
-                            .expect("utxo must exists");
-                        utxo.reserve = Some(rfq.order_id.clone());
-                    }
+            Msg::Notification(_) => {}
 
-                    swaps.insert(
-                        rfq.order_id,
-                        ActiveSwap {
-                            proposal: proposal.to_sat(),
-                            change_amount,
-                            sell_asset: rfq_recv_asset.asset_id.clone(),
-                            swap: None,
-                        },
-                    );
-                }
+            Msg::NewBlock => {
+                let settings = Arc::clone(&settings);
+                let shared = Arc::clone(&shared);
+                let rpc_http_client = rpc_http_client.clone();
+                tokio::spawn(async move {
+                    handle_new_block(settings, shared, rpc_http_client).await;
+                });
+            }
 
-                Notification::RfqRemoved(rfq) => {
-                    if rfq.status != RfqStatus::Accepted {
-                        free_reservation(&rfq.order_id, &mut utxos);
-                    }
-                }
+            Msg::Control(request, reply_tx) => {
+                let shared = Arc::clone(&shared);
+                tokio::spawn(async move {
+                    let response = handle_control_request(shared, request).await;
+                    let _ = reply_tx.send(response);
+                });
+            }
+        }
+    }
+}
 
-                Notification::Swap(swap) => {
-                    let active_swap = swaps.get_mut(&swap.order_id).expect("swap must exists");
-                    match &swap.state {
-                        SwapState::ReviewOffer(offer) => {
-                            info!("waiting user offer accept");
-                            assert!(!offer.accept_required);
-                            assert!(offer.swap.send_asset == active_swap.sell_asset);
-                            assert!(offer.swap.send_amount == active_swap.proposal);
-                            active_swap.swap = Some(offer.swap.clone());
-                        }
-                        SwapState::WaitPsbt => {
-                            let sw = active_swap.swap.as_ref().expect("swap must be set");
-                            let new_address = rpc::make_rpc_call::<String>(
-                                &rpc_http_client,
-                                &settings.rpc,
-                                &rpc::get_new_address(),
-                            )
-                            .expect("getting new address failed");
-
-                            let inputs: Vec<_> = utxos
-                                .values()
-                                .filter(|utxo| utxo.reserve.as_ref() == Some(&swap.order_id))
-                                .map(|utxo| utxo.item.tx_out())
-                                .collect();
-                            let mut outputs_amounts: BTreeMap<String, serde_json::Value> =
-                                BTreeMap::new();
-                            let mut outputs_assets: BTreeMap<String, String> = BTreeMap::new();
-
-                            outputs_amounts.insert(
-                                new_address.clone(),
-                                Amount::from_sat(sw.recv_amount).to_rpc(),
-                            );
-                            outputs_assets.insert(new_address.clone(), sw.recv_asset.clone());
-
-                            if active_swap.change_amount > 0 {
-                                let change_address = rpc::make_rpc_call::<String>(
-                                    &rpc_http_client,
-                                    &settings.rpc,
-                                    &rpc::get_new_address(),
-                                )
-                                .expect("getting new address failed");
-                                outputs_amounts.insert(
-                                    change_address.clone(),
-                                    Amount::from_sat(active_swap.change_amount).to_rpc(),
-                                );
-                                outputs_assets
-                                    .insert(change_address, active_swap.sell_asset.clone());
-                            }
-
-                            let raw_tx = rpc::make_rpc_call::<String>(
-                                &rpc_http_client,
-                                &settings.rpc,
-                                &rpc::create_raw_tx(
-                                    &inputs,
-                                    &outputs_amounts,
-                                    0,
-                                    false,
-                                    &outputs_assets,
-                                ),
-                            )
-                            .expect("creating raw tx failed");
-
-                            let psbt = rpc::make_rpc_call::<String>(
-                                &rpc_http_client,
-                                &settings.rpc,
-                                &rpc::convert_to_psbt(&raw_tx),
-                            )
-                            .expect("converting PSBT failed");
-
-                            let psbt = rpc::make_rpc_call::<rpc::FillPsbtData>(
-                                &rpc_http_client,
-                                &settings.rpc,
-                                &rpc::fill_psbt_data(&psbt),
-                            )
-                            .expect("converting PSBT failed");
-
-                            let _ = send_request!(
-                                Swap,
-                                SwapRequest {
-                                    order_id: swap.order_id.clone(),
-                                    action: SwapAction::Psbt(psbt.psbt),
-                                }
-                            )
-                            .map_err(|e| {
-                                error!("sending PSBT failed: {}", e);
-                            });
-                        }
-                        SwapState::WaitSign(psbt) => {
-                            let result = rpc::make_rpc_call::<rpc::WalletSignPsbt>(
-                                &rpc_http_client,
-                                &settings.rpc,
-                                &rpc::wallet_sign_psbt(&psbt),
-                            )
-                            .expect("signing PSBT failed");
-
-                            let _ = send_request!(
-                                Swap,
-                                SwapRequest {
-                                    order_id: swap.order_id.clone(),
-                                    action: SwapAction::Sign(result.psbt),
-                                }
-                            )
-                            .map_err(|e| {
-                                error!("sending signed PSBT failed: {}", e);
-                            });
-                        }
-                        SwapState::Failed(error) => {
-                            info!("swap failed: {:?}", error);
-                            free_reservation(&swap.order_id, &mut utxos);
-                        }
-                        SwapState::Done(txid) => {
-                            info!("swap succeed, txid: {}", &txid);
-                        }
-                    }
-                }
-                _ => {}
+async fn handle_rfq_created(
+    shared: Arc<tokio::sync::Mutex<SharedState>>,
+    request_sender: RequestSender,
+    max_trade_amount: Amount,
+    swap_timeout: std::time::Duration,
+    rfq: RfqCreated,
+) {
+    let mut state = shared.lock().await;
+
+    if state.trading_paused {
+        debug!("trading is paused, skipping RFQ: {}", &rfq.order_id);
+        return;
+    }
+
+    let rfq_recv_asset = state
+        .assets
+        .iter()
+        .find(|v| v.asset_id == rfq.rfq.recv_asset)
+        .expect("buy_asset must be known")
+        .clone();
+    let ref_send_asset = state
+        .assets
+        .iter()
+        .find(|v| v.asset_id == rfq.rfq.send_asset)
+        .expect("sell_asset must be known")
+        .clone();
+    info!(
+        "new RFQ received, order_id: {}, dealer deleiver: {}, dealer receive: {}",
+        &rfq.order_id, &rfq_recv_asset.ticker, &ref_send_asset.ticker
+    );
+
+    assert!(rfq_recv_asset.ticker == TICKER_LBTC || ref_send_asset.ticker == TICKER_LBTC);
+
+    let dealer_send_bitcoin = rfq_recv_asset.ticker == TICKER_LBTC;
+    let other_asset = if dealer_send_bitcoin {
+        &ref_send_asset
+    } else {
+        &rfq_recv_asset
+    };
+    let rfq_send_amount = Amount::from_sat(rfq.rfq.send_amount);
+
+    let proposal = dealer::get_proposal(state.profit_ratio, rfq_send_amount, other_asset, dealer_send_bitcoin);
+    let proposal = match proposal {
+        Ok(v) => v,
+        Err(e) => {
+            error!("can't get proposal: {}", e);
+            return;
+        }
+    };
+
+    let bitcoin_amount = if dealer_send_bitcoin {
+        proposal
+    } else {
+        rfq_send_amount
+    };
+
+    if bitcoin_amount > max_trade_amount {
+        info!(
+            "amount to trade is more than allowed: {} > {}",
+            bitcoin_amount, max_trade_amount
+        );
+        return;
+    }
+
+    let available_utxos: Vec<i64> = state
+        .utxos
+        .values()
+        .filter(|utxo| utxo.item.asset == rfq_recv_asset.asset_id && utxo.reserve.is_none())
+        .map(|utxo| utxo.amount)
+        .collect();
+    let total: i64 = available_utxos.iter().sum();
+    if total < proposal.to_sat() {
+        info!(
+            "not enough amount to make proposal: {}, required: {}",
+            total, proposal
+        );
+        return;
+    }
+
+    let result = types::select_utxo(available_utxos, proposal.to_sat());
+    let change_amount = result.iter().sum::<i64>() - proposal.to_sat();
+    assert!(change_amount >= 0);
+
+    let order_id = rfq.order_id.clone();
+
+    // Reserve the selected UTXOs right here, still holding the lock we used to select
+    // them, so a second RFQ arriving for the same asset can never observe and pick the
+    // same UTXOs (the previous design re-reserved after the quote round-trip, which left
+    // a window for two RFQs to select the same UTXOs and one of them to panic). If a
+    // selected amount somehow isn't reservable anymore, back out what we've already
+    // reserved and bail instead of panicking on untrusted timing.
+    for &amount in result.iter() {
+        let utxo = state.utxos.values_mut().find(|utxo| {
+            utxo.item.asset == rfq_recv_asset.asset_id && utxo.reserve.is_none() && utxo.amount == amount
+        });
+        match utxo {
+            Some(utxo) => utxo.reserve = Some(order_id.clone()),
+            None => {
+                error!(
+                    "utxo selected for order {} is no longer available, dropping RFQ",
+                    &order_id
+                );
+                free_reservation(&order_id, &mut state.utxos);
+                return;
+            }
+        }
+    }
+
+    state.swaps.insert(
+        order_id.clone(),
+        ActiveSwap {
+            proposal: proposal.to_sat(),
+            change_amount,
+            sell_asset: rfq_recv_asset.asset_id.clone(),
+            swap: None,
+            deadline: std::time::Instant::now() + swap_timeout,
+            busy: false,
+            bounce_txid: None,
+        },
+    );
+
+    // Release the lock for the RPC round-trip so other RFQs/swaps can keep progressing.
+    drop(state);
+
+    info!("sending quote: {}", proposal);
+    let quote_result = send_request!(
+        request_sender,
+        MatchQuote,
+        MatchQuoteRequest {
+            quote: MatchQuote {
+                order_id: order_id.clone(),
+                send_amount: proposal.to_sat(),
+                utxo_count: result.len() as i32,
+                with_change: change_amount > 0,
             },
+        }
+    );
+    if let Err(e) = quote_result {
+        error!("sending quote failed: {}", &e.message);
+        // The server never matched this quote, so roll back the optimistic reservation.
+        let mut state = shared.lock().await;
+        free_reservation(&order_id, &mut state.utxos);
+        state.swaps.remove(&order_id);
+        return;
+    }
+    debug!("sending quote succeed");
+}
 
-            Msg::NewBlock => {
-                debug!("new block detected");
-                let unspent_with_zc = rpc::make_rpc_call::<rpc::ListUnspent>(
+async fn handle_swap_notification(
+    settings: Arc<Settings>,
+    shared: Arc<tokio::sync::Mutex<SharedState>>,
+    request_sender: RequestSender,
+    rpc_http_client: reqwest::Client,
+    swap_timeout: std::time::Duration,
+    swap: Swap,
+) {
+    match &swap.state {
+        SwapState::ReviewOffer(offer) => {
+            let mut state = shared.lock().await;
+            if !mark_swap_active(&mut state.swaps, &swap.order_id, swap_timeout) {
+                warn!("swap {} no longer active, ignoring offer", &swap.order_id);
+                return;
+            }
+            let active_swap = state.swaps.get_mut(&swap.order_id).expect("just marked active");
+            info!("waiting user offer accept");
+            assert!(!offer.accept_required);
+            assert!(offer.swap.send_asset == active_swap.sell_asset);
+            assert!(offer.swap.send_amount == active_swap.proposal);
+            active_swap.swap = Some(offer.swap.clone());
+            active_swap.busy = false;
+        }
+        SwapState::WaitPsbt => {
+            let (sw, change_amount, sell_asset) = {
+                let mut state = shared.lock().await;
+                if !mark_swap_active(&mut state.swaps, &swap.order_id, swap_timeout) {
+                    warn!("swap {} no longer active, ignoring WaitPsbt", &swap.order_id);
+                    return;
+                }
+                let active_swap = state.swaps.get(&swap.order_id).expect("just marked active");
+                (
+                    active_swap.swap.clone().expect("swap must be set"),
+                    active_swap.change_amount,
+                    active_swap.sell_asset.clone(),
+                )
+            };
+
+            let new_address =
+                rpc::make_rpc_call::<String>(&rpc_http_client, &settings.rpc, &rpc::get_new_address())
+                    .await
+                    .expect("getting new address failed");
+
+            let inputs: Vec<_> = {
+                let state = shared.lock().await;
+                state
+                    .utxos
+                    .values()
+                    .filter(|utxo| utxo.reserve.as_ref() == Some(&swap.order_id))
+                    .map(|utxo| utxo.item.tx_out())
+                    .collect()
+            };
+
+            let mut outputs_amounts: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+            let mut outputs_assets: BTreeMap<String, String> = BTreeMap::new();
+
+            outputs_amounts.insert(new_address.clone(), Amount::from_sat(sw.recv_amount).to_rpc());
+            outputs_assets.insert(new_address.clone(), sw.recv_asset.clone());
+
+            if change_amount > 0 {
+                let change_address = rpc::make_rpc_call::<String>(
                     &rpc_http_client,
                     &settings.rpc,
-                    &rpc::list_unspent2(0),
+                    &rpc::get_new_address(),
                 )
-                .expect("list_unspent failed");
-
-                let unspent: rpc::ListUnspent = unspent_with_zc
-                    .into_iter()
-                    .filter(|utxo| utxo.confirmations >= UNSPENT_MIN_CONF)
-                    .collect();
-                let old_keys: BTreeSet<_> = utxos.keys().cloned().collect();
-                let new_keys: BTreeSet<_> = unspent.iter().map(|item| item.tx_out()).collect();
-                for item in unspent {
-                    utxos.entry(item.tx_out()).or_insert(Utxo {
-                        amount: Amount::from_rpc(&item.amount).to_sat(),
-                        item,
-                        reserve: None,
-                    });
+                .await
+                .expect("getting new address failed");
+                outputs_amounts.insert(change_address.clone(), Amount::from_sat(change_amount).to_rpc());
+                outputs_assets.insert(change_address, sell_asset);
+            }
+
+            let op_return_data = if settings.settlement_tag.is_empty() {
+                None
+            } else {
+                let mut data = settings.settlement_tag.clone().into_bytes();
+                data.extend_from_slice(swap.order_id.to_string().as_bytes());
+                if data.len() > rpc::OP_RETURN_STANDARDNESS_LIMIT {
+                    warn!(
+                        "settlement metadata for order {} exceeds the {}-byte OP_RETURN standardness limit, skipping",
+                        swap.order_id,
+                        rpc::OP_RETURN_STANDARDNESS_LIMIT
+                    );
+                    None
+                } else {
+                    Some(data)
+                }
+            };
+
+            let raw_tx = rpc::make_rpc_call::<String>(
+                &rpc_http_client,
+                &settings.rpc,
+                &rpc::create_raw_tx(
+                    &inputs,
+                    &outputs_amounts,
+                    0,
+                    false,
+                    &outputs_assets,
+                    op_return_data.as_deref(),
+                ),
+            )
+            .await
+            .expect("creating raw tx failed");
+
+            let psbt = rpc::make_rpc_call::<String>(&rpc_http_client, &settings.rpc, &rpc::convert_to_psbt(&raw_tx))
+                .await
+                .expect("converting PSBT failed");
+
+            let psbt = rpc::make_rpc_call::<rpc::FillPsbtData>(
+                &rpc_http_client,
+                &settings.rpc,
+                &rpc::fill_psbt_data(&psbt),
+            )
+            .await
+            .expect("converting PSBT failed");
+
+            let _ = send_request!(
+                request_sender,
+                Swap,
+                SwapRequest {
+                    order_id: swap.order_id.clone(),
+                    action: SwapAction::Psbt(psbt.psbt),
                 }
-                for key in old_keys.difference(&new_keys) {
-                    debug!("remove consumed utxo: {}/{}", &key.txid, key.vout);
-                    utxos.remove(&key);
+            )
+            .map_err(|e| {
+                error!("sending PSBT failed: {}", e);
+            });
+
+            mark_swap_idle(&mut shared.lock().await.swaps, &swap.order_id);
+        }
+        SwapState::WaitSign(psbt) => {
+            if !mark_swap_active(&mut shared.lock().await.swaps, &swap.order_id, swap_timeout) {
+                warn!("swap {} no longer active, ignoring WaitSign", &swap.order_id);
+                return;
+            }
+
+            let result = rpc::make_rpc_call::<rpc::WalletSignPsbt>(
+                &rpc_http_client,
+                &settings.rpc,
+                &rpc::wallet_sign_psbt(psbt),
+            )
+            .await
+            .expect("signing PSBT failed");
+
+            let _ = send_request!(
+                request_sender,
+                Swap,
+                SwapRequest {
+                    order_id: swap.order_id.clone(),
+                    action: SwapAction::Sign(result.psbt),
+                }
+            )
+            .map_err(|e| {
+                error!("sending signed PSBT failed: {}", e);
+            });
+
+            mark_swap_idle(&mut shared.lock().await.swaps, &swap.order_id);
+        }
+        SwapState::Failed(error) => {
+            info!("swap failed: {:?}", error);
+            let mut state = shared.lock().await;
+            free_reservation(&swap.order_id, &mut state.utxos);
+            state.swaps.remove(&swap.order_id);
+        }
+        SwapState::Done(txid) => {
+            info!("swap succeed, txid: {}", &txid);
+            shared.lock().await.swaps.remove(&swap.order_id);
+        }
+    }
+}
+
+async fn handle_new_block(
+    settings: Arc<Settings>,
+    shared: Arc<tokio::sync::Mutex<SharedState>>,
+    rpc_http_client: reqwest::Client,
+) {
+    debug!("new block detected");
+    let unspent_with_zc = rpc::make_rpc_call::<rpc::ListUnspent>(
+        &rpc_http_client,
+        &settings.rpc,
+        &rpc::list_unspent2(0),
+    )
+    .await
+    .expect("list_unspent failed");
+
+    let unspent: rpc::ListUnspent = unspent_with_zc
+        .into_iter()
+        .filter(|utxo| utxo.confirmations >= UNSPENT_MIN_CONF)
+        .collect();
+
+    {
+        let mut state = shared.lock().await;
+        let old_keys: BTreeSet<_> = state.utxos.keys().cloned().collect();
+        let new_keys: BTreeSet<_> = unspent.iter().map(|item| item.tx_out()).collect();
+        for item in unspent {
+            state.utxos.entry(item.tx_out()).or_insert(Utxo {
+                amount: Amount::from_rpc(&item.amount).to_sat(),
+                item,
+                reserve: None,
+            });
+        }
+        for key in old_keys.difference(&new_keys) {
+            debug!("remove consumed utxo: {}/{}", &key.txid, key.vout);
+            state.utxos.remove(&key);
+        }
+    }
+
+    let now = std::time::Instant::now();
+    let stalled: Vec<OrderId> = {
+        let state = shared.lock().await;
+        state
+            .swaps
+            .iter()
+            .filter(|(_, swap)| !swap.busy && swap.bounce_txid.is_none() && swap.deadline <= now)
+            .map(|(order_id, _)| order_id.clone())
+            .collect()
+    };
+
+    for order_id in stalled {
+        bounce_stalled_swap(&settings, &shared, &rpc_http_client, order_id).await;
+    }
+}
+
+async fn bounce_stalled_swap(
+    settings: &Settings,
+    shared: &tokio::sync::Mutex<SharedState>,
+    rpc_http_client: &reqwest::Client,
+    order_id: OrderId,
+) {
+    warn!(
+        "swap {} stalled past its deadline, bouncing reserved funds",
+        &order_id
+    );
+
+    let bounce_inputs: Vec<(TxOut, i64, String)> = {
+        let mut state = shared.lock().await;
+        // Re-check busy under the same lock used to free the reservation: if a
+        // notification handler picked this swap back up since it was selected as
+        // stalled, it owns these UTXOs now and bouncing them out from under it would
+        // leave that handler's settlement RPC calls operating on foreign/missing inputs.
+        match state.swaps.get(&order_id) {
+            Some(active_swap) if active_swap.busy => {
+                debug!("swap {} became active again, skipping this bounce attempt", &order_id);
+                return;
+            }
+            Some(_) => {}
+            None => return,
+        }
+        let bounce_inputs = state
+            .utxos
+            .values()
+            .filter(|utxo| utxo.reserve.as_ref() == Some(&order_id))
+            .map(|utxo| (utxo.item.tx_out(), utxo.amount, utxo.item.asset.clone()))
+            .collect();
+        free_reservation(&order_id, &mut state.utxos);
+        bounce_inputs
+    };
+
+    if bounce_inputs.is_empty() {
+        if let Some(active_swap) = shared.lock().await.swaps.get_mut(&order_id) {
+            active_swap.bounce_txid = Some(String::new());
+        }
+        return;
+    }
+
+    let inputs: Vec<TxOut> = bounce_inputs.iter().map(|(tx_out, _, _)| tx_out.clone()).collect();
+    let mut totals_by_asset: BTreeMap<String, i64> = BTreeMap::new();
+    for (_, amount, asset) in &bounce_inputs {
+        *totals_by_asset.entry(asset.clone()).or_insert(0) += amount;
+    }
+
+    let refund_address = rpc::make_rpc_call::<String>(rpc_http_client, &settings.rpc, &rpc::get_new_address())
+        .await
+        .expect("getting bounce address failed");
+
+    let mut outputs_amounts: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+    let mut outputs_assets: BTreeMap<String, String> = BTreeMap::new();
+    for (asset, total) in totals_by_asset {
+        let refund_amount = total - settings.bounce_fee;
+        if refund_amount <= 0 {
+            warn!(
+                "bounce fee exceeds reserved amount for swap {}, nothing to refund for asset {}",
+                &order_id, asset
+            );
+            continue;
+        }
+        outputs_amounts.insert(refund_address.clone(), Amount::from_sat(refund_amount).to_rpc());
+        outputs_assets.insert(refund_address.clone(), asset);
+    }
+
+    if outputs_amounts.is_empty() {
+        if let Some(active_swap) = shared.lock().await.swaps.get_mut(&order_id) {
+            active_swap.bounce_txid = Some(String::new());
+        }
+        return;
+    }
+
+    let raw_tx = rpc::make_rpc_call::<String>(
+        rpc_http_client,
+        &settings.rpc,
+        &rpc::create_raw_tx(&inputs, &outputs_amounts, 0, false, &outputs_assets, None),
+    )
+    .await
+    .expect("creating bounce tx failed");
+
+    let psbt = rpc::make_rpc_call::<String>(rpc_http_client, &settings.rpc, &rpc::convert_to_psbt(&raw_tx))
+        .await
+        .expect("converting bounce PSBT failed");
+
+    // Same as the settlement path: the refund output doesn't balance against the inputs
+    // by exactly `bounce_fee`, so the wallet needs to fill in blinding data and the fee
+    // output itself before it can sign.
+    let psbt = rpc::make_rpc_call::<rpc::FillPsbtData>(rpc_http_client, &settings.rpc, &rpc::fill_psbt_data(&psbt))
+        .await
+        .expect("filling bounce PSBT data failed");
+
+    let signed = rpc::make_rpc_call::<rpc::WalletSignPsbt>(
+        rpc_http_client,
+        &settings.rpc,
+        &rpc::wallet_sign_psbt(&psbt.psbt),
+    )
+    .await
+    .expect("signing bounce PSBT failed");
+
+    let finalized = rpc::make_rpc_call::<rpc::FinalizedPsbt>(
+        rpc_http_client,
+        &settings.rpc,
+        &rpc::finalize_psbt(&signed.psbt),
+    )
+    .await
+    .expect("finalizing bounce PSBT failed");
+
+    let txid = match finalized.hex {
+        Some(hex) => rpc::make_rpc_call::<String>(rpc_http_client, &settings.rpc, &rpc::send_raw_transaction(&hex))
+            .await
+            .expect("broadcasting bounce tx failed"),
+        None => {
+            error!(
+                "bounce PSBT for swap {} did not finalize, will retry on next block",
+                &order_id
+            );
+            // Reserve the inputs again so the retry on the next block finds them.
+            let mut state = shared.lock().await;
+            for tx_out in &inputs {
+                if let Some(utxo) = state.utxos.get_mut(tx_out) {
+                    if utxo.reserve.is_none() {
+                        utxo.reserve = Some(order_id.clone());
+                    }
                 }
             }
+            return;
         }
+    };
+
+    info!("bounced swap {}, txid: {}", &order_id, &txid);
+    if let Some(active_swap) = shared.lock().await.swaps.get_mut(&order_id) {
+        active_swap.bounce_txid = Some(txid);
+    }
+}
+
+async fn handle_control_request(
+    shared: Arc<tokio::sync::Mutex<SharedState>>,
+    request: control_rpc::ControlRequest,
+) -> control_rpc::ControlResponse {
+    let mut state = shared.lock().await;
+    match request {
+        control_rpc::ControlRequest::ListActiveSwaps => {
+            let list = state
+                .swaps
+                .iter()
+                .map(|(order_id, swap)| control_rpc::ActiveSwapInfo {
+                    order_id: order_id.to_string(),
+                    proposal: swap.proposal,
+                    change_amount: swap.change_amount,
+                    sell_asset: swap.sell_asset.clone(),
+                    state: match &swap.swap {
+                        Some(_) => "offer_accepted",
+                        None => "awaiting_offer",
+                    },
+                })
+                .collect();
+            control_rpc::ControlResponse::ActiveSwaps(list)
+        }
+        control_rpc::ControlRequest::ListReservedUtxos => {
+            let list = state
+                .utxos
+                .iter()
+                .filter_map(|(tx_out, utxo)| {
+                    utxo.reserve.as_ref().map(|order_id| control_rpc::ReservedUtxoInfo {
+                        txid: tx_out.txid.to_string(),
+                        vout: tx_out.vout,
+                        amount: utxo.amount,
+                        order_id: order_id.to_string(),
+                    })
+                })
+                .collect();
+            control_rpc::ControlResponse::ReservedUtxos(list)
+        }
+        control_rpc::ControlRequest::GetInventory => {
+            let mut totals: BTreeMap<String, i64> = BTreeMap::new();
+            for utxo in state.utxos.values() {
+                *totals.entry(utxo.item.asset.clone()).or_insert(0) += utxo.amount;
+            }
+            let list = totals
+                .into_iter()
+                .map(|(asset_id, amount)| control_rpc::InventoryEntry { asset_id, amount })
+                .collect();
+            control_rpc::ControlResponse::Inventory(list)
+        }
+        control_rpc::ControlRequest::PauseTrading => {
+            info!("trading paused via control RPC");
+            state.trading_paused = true;
+            control_rpc::ControlResponse::Ack
+        }
+        control_rpc::ControlRequest::ResumeTrading => {
+            info!("trading resumed via control RPC");
+            state.trading_paused = false;
+            control_rpc::ControlResponse::Ack
+        }
+        control_rpc::ControlRequest::SetProfitRatio { profit_ratio } => {
+            // `set_profit_ratio` already rejects anything below `MIN_PROFIT_RATIO` with a
+            // proper RPC error before it ever reaches here, so this is trusted input.
+            info!("profit ratio updated via control RPC: {}", profit_ratio);
+            state.profit_ratio = profit_ratio;
+            control_rpc::ControlResponse::Ack
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_request_sender() -> RequestSender {
+        let (ws_tx, _ws_rx) = std::sync::mpsc::channel();
+        RequestSender {
+            ws_tx,
+            current_request_id: Arc::new(AtomicI64::new(0)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[test]
+    fn takes_and_removes_the_waiter_for_a_known_request_id() {
+        let sender = test_request_sender();
+        let (tx, _rx) = oneshot::channel();
+        sender.pending.lock().unwrap().insert(7, tx);
+
+        assert!(sender.take_pending(7).is_some());
+        // The entry must be consumed, not just peeked, so a duplicate response can't be
+        // routed to a waiter that already got its answer.
+        assert!(sender.take_pending(7).is_none());
+    }
+
+    #[test]
+    fn drops_responses_with_no_matching_waiter_instead_of_panicking() {
+        let sender = test_request_sender();
+
+        assert!(sender.take_pending(42).is_none());
     }
 }
@@ -0,0 +1,32 @@
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+// Rates are the price of one unit of the asset, expressed in BTC.
+static RATES: RwLock<BTreeMap<String, Decimal>> = RwLock::new(BTreeMap::new());
+
+pub fn update_rate(asset_id: String, rate_in_btc: Decimal) {
+    RATES
+        .write()
+        .expect("rates lock poisoned")
+        .insert(asset_id, rate_in_btc);
+}
+
+// Returns the multiplier to apply to a `send_amount` so it converts into the
+// opposite side of the swap: the price of the asset in BTC when the dealer is
+// sending bitcoin, or its reciprocal when the dealer is sending the asset.
+pub fn get_rate(asset_id: &str, dealer_send_bitcoin: bool) -> anyhow::Result<Decimal> {
+    let rate_in_btc = *RATES
+        .read()
+        .expect("rates lock poisoned")
+        .get(asset_id)
+        .ok_or_else(|| anyhow!("no rate available for asset: {}", asset_id))?;
+
+    if dealer_send_bitcoin {
+        Ok(rate_in_btc)
+    } else {
+        Decimal::from(1)
+            .checked_div(rate_in_btc)
+            .ok_or_else(|| anyhow!("can't invert rate for asset: {}", asset_id))
+    }
+}
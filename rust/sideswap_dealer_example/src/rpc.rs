@@ -0,0 +1,209 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use types::TxOut;
+
+// The standardness limit Bitcoin/Elements nodes enforce on a single OP_RETURN payload.
+pub const OP_RETURN_STANDARDNESS_LIMIT: usize = 80;
+
+#[derive(Debug, Deserialize)]
+pub struct RpcServer {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+}
+
+pub struct RpcCall {
+    method: &'static str,
+    params: Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnspentItem {
+    pub txid: String,
+    pub vout: u32,
+    pub address: String,
+    pub asset: String,
+    pub amount: Value,
+    pub confirmations: i32,
+}
+
+impl UnspentItem {
+    pub fn tx_out(&self) -> TxOut {
+        TxOut {
+            txid: self.txid.clone(),
+            vout: self.vout,
+        }
+    }
+}
+
+pub type ListUnspent = Vec<UnspentItem>;
+
+pub fn list_unspent2(min_conf: i32) -> RpcCall {
+    RpcCall {
+        method: "listunspent",
+        params: json!([min_conf]),
+    }
+}
+
+pub fn get_new_address() -> RpcCall {
+    RpcCall {
+        method: "getnewaddress",
+        params: json!([]),
+    }
+}
+
+// Builds a `createrawtransaction` call. When `op_return_data` is non-empty, it is
+// embedded as an extra nulldata output via the RPC's special `"data"` output key,
+// so settled swaps carry an on-chain identifier for later reconciliation.
+pub fn create_raw_tx(
+    inputs: &[TxOut],
+    outputs_amounts: &BTreeMap<String, Value>,
+    locktime: i64,
+    replaceable: bool,
+    outputs_assets: &BTreeMap<String, String>,
+    op_return_data: Option<&[u8]>,
+) -> RpcCall {
+    let mut outputs_amounts = outputs_amounts.clone();
+    if let Some(data) = op_return_data {
+        if !data.is_empty() {
+            assert!(
+                data.len() <= OP_RETURN_STANDARDNESS_LIMIT,
+                "OP_RETURN payload exceeds the {}-byte standardness limit",
+                OP_RETURN_STANDARDNESS_LIMIT
+            );
+            outputs_amounts.insert("data".to_owned(), json!(hex::encode(data)));
+        }
+    }
+
+    RpcCall {
+        method: "createrawtransaction",
+        params: json!([inputs, outputs_amounts, locktime, replaceable, outputs_assets]),
+    }
+}
+
+pub fn convert_to_psbt(raw_tx: &str) -> RpcCall {
+    RpcCall {
+        method: "converttopsbt",
+        params: json!([raw_tx]),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FillPsbtData {
+    pub psbt: String,
+}
+
+pub fn fill_psbt_data(psbt: &str) -> RpcCall {
+    RpcCall {
+        method: "walletfillpsbtdata",
+        params: json!([psbt]),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WalletSignPsbt {
+    pub psbt: String,
+}
+
+pub fn wallet_sign_psbt(psbt: &str) -> RpcCall {
+    RpcCall {
+        method: "walletsignpsbt",
+        params: json!([psbt]),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FinalizedPsbt {
+    pub hex: Option<String>,
+    pub complete: bool,
+}
+
+pub fn finalize_psbt(psbt: &str) -> RpcCall {
+    RpcCall {
+        method: "finalizepsbt",
+        params: json!([psbt]),
+    }
+}
+
+pub fn send_raw_transaction(hex: &str) -> RpcCall {
+    RpcCall {
+        method: "sendrawtransaction",
+        params: json!([hex]),
+    }
+}
+
+pub async fn make_rpc_call<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    server: &RpcServer,
+    call: &RpcCall,
+) -> anyhow::Result<T> {
+    let url = format!("http://{}:{}/", server.host, server.port);
+    let body = json!({
+        "jsonrpc": "1.0",
+        "id": "sideswap_dealer",
+        "method": call.method,
+        "params": call.params,
+    });
+
+    let response: Value = client
+        .post(&url)
+        .basic_auth(&server.user, Some(&server.password))
+        .json(&body)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if let Some(error) = response.get("error") {
+        if !error.is_null() {
+            bail!("RPC call {} failed: {}", call.method, error);
+        }
+    }
+
+    let result = response
+        .get("result")
+        .ok_or_else(|| anyhow!("RPC call {} returned no result", call.method))?;
+
+    Ok(serde_json::from_value(result.clone())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_outputs() -> (BTreeMap<String, Value>, BTreeMap<String, String>) {
+        (BTreeMap::new(), BTreeMap::new())
+    }
+
+    #[test]
+    fn embeds_op_return_data_at_the_standardness_limit() {
+        let (outputs_amounts, outputs_assets) = empty_outputs();
+        let data = vec![0xab; OP_RETURN_STANDARDNESS_LIMIT];
+
+        let call = create_raw_tx(&[], &outputs_amounts, 0, false, &outputs_assets, Some(&data));
+
+        let outputs = call.params[1].as_object().expect("outputs must be an object");
+        assert_eq!(outputs["data"], json!(hex::encode(&data)));
+    }
+
+    #[test]
+    #[should_panic(expected = "OP_RETURN payload exceeds the 80-byte standardness limit")]
+    fn rejects_op_return_data_over_the_standardness_limit() {
+        let (outputs_amounts, outputs_assets) = empty_outputs();
+        let data = vec![0xab; OP_RETURN_STANDARDNESS_LIMIT + 1];
+
+        create_raw_tx(&[], &outputs_amounts, 0, false, &outputs_assets, Some(&data));
+    }
+
+    #[test]
+    fn omits_the_data_output_when_op_return_data_is_empty() {
+        let (outputs_amounts, outputs_assets) = empty_outputs();
+
+        let call = create_raw_tx(&[], &outputs_amounts, 0, false, &outputs_assets, Some(&[]));
+
+        let outputs = call.params[1].as_object().expect("outputs must be an object");
+        assert!(!outputs.contains_key("data"));
+    }
+}